@@ -1,7 +1,11 @@
 use crate::diffs::Change;
 use regex::Regex;
 use std::cmp::{max, min};
-use std::collections::HashSet;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
 
 #[cfg(test)]
 use pretty_assertions::assert_eq;
@@ -14,6 +18,32 @@ pub struct AbsoluteRange {
     pub end: usize, // exclusive
 }
 
+/// The line terminator used by a file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LineTerminator {
+    Lf,
+    CrLf,
+}
+
+impl LineTerminator {
+    /// Detect the line terminator used by `s`, by looking at the first
+    /// newline. Defaults to `Lf` if `s` has no newline.
+    pub fn detect(s: &str) -> LineTerminator {
+        match s.find('\n') {
+            Some(i) if i > 0 && s.as_bytes()[i - 1] == b'\r' => LineTerminator::CrLf,
+            _ => LineTerminator::Lf,
+        }
+    }
+
+    /// The literal string for this terminator.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LineTerminator::Lf => "\n",
+            LineTerminator::CrLf => "\r\n",
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 pub struct LineNumber {
     pub number: usize,
@@ -48,6 +78,10 @@ pub struct LineRange {
 pub struct NewlinePositions {
     /// A vector of the start positions of all the lines in `s`.
     positions: Vec<usize>,
+    /// Whether each line (indexed the same as the newlines that
+    /// terminate them) ends with `\r\n` rather than a bare `\n`.
+    /// Detected per line, since a file can mix line endings.
+    line_has_cr: Vec<bool>,
 }
 
 impl NewlinePositions {
@@ -55,28 +89,40 @@ impl NewlinePositions {
         let newline_re = Regex::new("\n").unwrap();
         let newlines: Vec<_> = newline_re.find_iter(s).map(|mat| mat.end()).collect();
 
+        let bytes = s.as_bytes();
+        let line_has_cr: Vec<bool> = newlines
+            .iter()
+            .map(|&newline_end| newline_end >= 2 && bytes[newline_end - 2] == b'\r')
+            .collect();
+
         let mut positions = Vec::with_capacity(newlines.len() + 1);
         positions.push(0);
         positions.extend(&newlines);
 
         NewlinePositions {
             positions: positions,
+            line_has_cr,
         }
     }
 
-    pub fn from_offset(self: &NewlinePositions, offset: usize) -> LinePosition {
-        for line_num in (0..self.positions.len()).rev() {
-            if offset >= self.positions[line_num as usize] {
-                return LinePosition {
-                    line: LineNumber::from(line_num as usize),
-                    column: offset - self.positions[line_num as usize],
-                };
-            }
+    /// The number of bytes occupied by the carriage return ending
+    /// `line_num`, if any.
+    fn cr_len(self: &NewlinePositions, line_num: usize) -> usize {
+        if self.line_has_cr.get(line_num).copied().unwrap_or(false) {
+            1
+        } else {
+            0
         }
+    }
+
+    pub fn from_offset(self: &NewlinePositions, offset: usize) -> LinePosition {
+        // `positions` is sorted in ascending order, so binary search for
+        // the last line whose start position is <= offset.
+        let line_num = self.positions.partition_point(|&p| p <= offset) - 1;
 
         LinePosition {
-            line: LineNumber::from(0),
-            column: offset,
+            line: LineNumber::from(line_num),
+            column: offset - self.positions[line_num],
         }
     }
 
@@ -97,7 +143,8 @@ impl NewlinePositions {
             });
             return ranges;
         } else {
-            let first_line_end_pos = self.positions[start.line.number + 1] - 1;
+            let first_line_end_pos =
+                self.positions[start.line.number + 1] - 1 - self.cr_len(start.line.number);
             let first_line_length = first_line_end_pos - self.positions[start.line.number];
             ranges.push(LineRange {
                 line: start.line,
@@ -107,7 +154,7 @@ impl NewlinePositions {
         }
 
         for line_num in (start.line.number + 1)..end.line.number {
-            let line_end_pos = self.positions[line_num + 1] - 1;
+            let line_end_pos = self.positions[line_num + 1] - 1 - self.cr_len(line_num);
             let line_length = line_end_pos - self.positions[line_num];
             ranges.push(LineRange {
                 line: LineNumber::from(line_num),
@@ -140,6 +187,37 @@ impl NewlinePositions {
 
         rel_positions
     }
+
+    /// Convert a line-relative position back to an absolute string
+    /// offset. This is the inverse of `from_offset`, except that a
+    /// column past the end of the line's content is clamped to that end
+    /// (before any `\r\n` or `\n`), so offsets inside a line terminator
+    /// do not round-trip.
+    pub fn to_offset(self: &NewlinePositions, pos: LinePosition) -> usize {
+        let line_start = self.positions[pos.line.number];
+        let offset = line_start + pos.column;
+
+        match self.positions.get(pos.line.number + 1) {
+            Some(&next_line_start) => min(offset, next_line_start - 1 - self.cr_len(pos.line.number)),
+            None => offset,
+        }
+    }
+
+    /// Convert a line-relative range back to an absolute string range.
+    /// This is the inverse of `from_ranges` for ranges that don't span
+    /// multiple lines.
+    pub fn line_range_to_absolute(self: &NewlinePositions, range: LineRange) -> AbsoluteRange {
+        AbsoluteRange {
+            start: self.to_offset(LinePosition {
+                line: range.line,
+                column: range.start,
+            }),
+            end: self.to_offset(LinePosition {
+                line: range.line,
+                column: range.end,
+            }),
+        }
+    }
 }
 
 #[test]
@@ -149,6 +227,13 @@ fn from_offset_newline_boundary() {
     assert_eq!(position, LinePosition { line: LineNumber::from(1), column: 0});
 }
 
+#[test]
+fn from_offset_past_end_of_string() {
+    let newline_positions = NewlinePositions::from("abc\nbar");
+    let position = newline_positions.from_offset(7);
+    assert_eq!(position, LinePosition { line: LineNumber::from(1), column: 3});
+}
+
 #[test]
 fn from_ranges_first_line() {
     let newline_positions = NewlinePositions::from("foo");
@@ -185,23 +270,155 @@ fn from_ranges_split_over_multiple_lines() {
     );
 }
 
+#[test]
+fn to_offset_round_trip() {
+    let newline_positions = NewlinePositions::from("foo\nbar\nbaz");
+    let position = newline_positions.from_offset(5);
+    assert_eq!(newline_positions.to_offset(position), 5);
+}
+
+#[test]
+fn line_range_to_absolute_round_trip() {
+    let newline_positions = NewlinePositions::from("foo\nbar\nbaz");
+    let line_range = LineRange {
+        line: LineNumber::from(1),
+        start: 1,
+        end: 3,
+    };
+    assert_eq!(
+        newline_positions.line_range_to_absolute(line_range),
+        AbsoluteRange { start: 5, end: 7 }
+    );
+}
+
+#[test]
+fn line_range_to_absolute_crlf_excludes_carriage_return() {
+    let newline_positions = NewlinePositions::from("foo\r\nbar\r\nbaz");
+    // An end column one past the line's content (as `split_line_boundaries`
+    // would never itself produce, but a caller's `LineRange` might, e.g.
+    // after widening a range) should still clamp before the `\r`, not
+    // land on it.
+    let line_range = LineRange {
+        line: LineNumber::from(1),
+        start: 0,
+        end: 4,
+    };
+    assert_eq!(
+        newline_positions.line_range_to_absolute(line_range),
+        AbsoluteRange { start: 5, end: 8 }
+    );
+}
+
+#[test]
+fn from_ranges_crlf_excludes_carriage_return() {
+    let newline_positions = NewlinePositions::from("foo\r\nbar\r\nbaz");
+    let relative_ranges = newline_positions.from_ranges(&vec![AbsoluteRange { start: 6, end: 11 }]);
+
+    assert_eq!(
+        relative_ranges,
+        vec![
+            (LineRange {
+                line: LineNumber::from(1),
+                start: 1,
+                end: 3
+            }),
+            (LineRange {
+                line: LineNumber::from(2),
+                start: 0,
+                end: 1
+            })
+        ]
+    );
+}
+
+#[test]
+fn from_ranges_mixed_line_endings() {
+    // Line 0 ends with `\r\n`, line 1 ends with a bare `\n`: the
+    // terminator must be detected per line, or line 1's length would be
+    // computed as if it too had a `\r`, dropping its last character.
+    let newline_positions = NewlinePositions::from("foo\r\nbar\nbaz");
+    let relative_ranges = newline_positions.from_ranges(&vec![AbsoluteRange { start: 1, end: 10 }]);
+
+    assert_eq!(
+        relative_ranges,
+        vec![
+            (LineRange {
+                line: LineNumber::from(0),
+                start: 1,
+                end: 3
+            }),
+            (LineRange {
+                line: LineNumber::from(1),
+                start: 0,
+                end: 3
+            }),
+            (LineRange {
+                line: LineNumber::from(2),
+                start: 0,
+                end: 1
+            })
+        ]
+    );
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct MatchedLine {
     pub line: LineNumber,
     pub opposite_line: LineNumber,
 }
 
+/// A byte that can be part of a word: an ASCII letter, digit, or
+/// underscore. This mirrors the `is_word_byte` convention used by
+/// line-diff libraries.
+fn is_word_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// Snap `range` outward to word boundaries in `s`: if `range.start`
+/// lands inside a word, move it back to the word's first byte, and if
+/// `range.end` lands inside a word, move it forward to just past the
+/// word's last byte. Since newline bytes are never word bytes, this
+/// never crosses a newline.
+fn snap_range_to_word_boundaries(s: &str, range: AbsoluteRange) -> AbsoluteRange {
+    let bytes = s.as_bytes();
+
+    let mut start = range.start;
+    if start > 0 && start < bytes.len() && is_word_byte(bytes[start - 1]) && is_word_byte(bytes[start]) {
+        while start > 0 && is_word_byte(bytes[start - 1]) {
+            start -= 1;
+        }
+    }
+
+    let mut end = range.end;
+    if end > 0 && end < bytes.len() && is_word_byte(bytes[end - 1]) && is_word_byte(bytes[end]) {
+        while end < bytes.len() && is_word_byte(bytes[end]) {
+            end += 1;
+        }
+    }
+
+    AbsoluteRange { start, end }
+}
+
 /// Given a slice of changes, return the unique lines that
-/// they land on, plus their corresponding line in the other file.
-pub fn relevant_lines(changes: &[Change], s: &str) -> Vec<MatchedLine> {
+/// they land on, plus their corresponding line in the other file. If
+/// `snap_to_word_boundaries` is set, each change's range is widened to
+/// the nearest word boundaries first, so highlighted spans present
+/// whole tokens rather than splitting an identifier mid-word.
+pub fn relevant_lines(changes: &[Change], s: &str, snap_to_word_boundaries: bool) -> Vec<MatchedLine> {
     let newlines = NewlinePositions::from(s);
 
     let mut line_nums_seen = HashSet::new();
 
     let mut result = vec![];
     for change in changes {
+        let range = if snap_to_word_boundaries {
+            snap_range_to_word_boundaries(s, change.range)
+        } else {
+            change.range
+        };
+
         // TODO: refactor to from_range.
-        let line_relative_ranges = newlines.from_ranges(&[change.range]);
+        let line_relative_ranges = newlines.from_ranges(&[range]);
         for range in line_relative_ranges {
             if line_nums_seen.contains(&range.line) {
                 continue;
@@ -218,18 +435,194 @@ pub fn relevant_lines(changes: &[Change], s: &str) -> Vec<MatchedLine> {
     result
 }
 
+#[test]
+fn snap_range_to_word_boundaries_widens_mid_word_range() {
+    let range = snap_range_to_word_boundaries("foo.bar_baz(1)", AbsoluteRange { start: 6, end: 10 });
+    assert_eq!(range, AbsoluteRange { start: 4, end: 11 });
+}
+
+#[test]
+fn snap_range_to_word_boundaries_leaves_boundary_aligned_range() {
+    let range = snap_range_to_word_boundaries("foo.bar", AbsoluteRange { start: 4, end: 7 });
+    assert_eq!(range, AbsoluteRange { start: 4, end: 7 });
+}
+
+/// The minimum number of non-whitespace characters a line must have to be
+/// considered for anchoring. This avoids anchoring on boilerplate lines
+/// (e.g. a lone closing brace) that occur identically all over a file.
+const ANCHOR_MIN_NON_WHITESPACE_LEN: usize = 32;
+
+/// Hash `line`'s content for anchoring purposes, ignoring leading
+/// whitespace. Returns `None` for lines too short to be a reliable
+/// anchor.
+fn anchor_hash(line: &str) -> Option<u64> {
+    let trimmed = line.trim_start();
+    if trimmed.chars().filter(|c| !c.is_whitespace()).count() < ANCHOR_MIN_NON_WHITESPACE_LEN {
+        return None;
+    }
+
+    let mut hasher = DefaultHasher::new();
+    trimmed.hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Index the lines of `s` by `anchor_hash`, keeping the line number and
+/// trimmed content of every line that hashes, so callers can confirm a
+/// hash match against the real content before trusting it.
+fn index_lines_by_hash(s: &str) -> HashMap<u64, Vec<(usize, &str)>> {
+    let mut index: HashMap<u64, Vec<(usize, &str)>> = HashMap::new();
+    for (i, line) in s.lines().enumerate() {
+        if let Some(hash) = anchor_hash(line) {
+            index.entry(hash).or_default().push((i, line.trim_start()));
+        }
+    }
+    index
+}
+
+/// Build unchanged-line anchors between `lhs` and `rhs`: pairs of line
+/// numbers whose content hashes to a value that occurs exactly once on
+/// each side (the hash match is confirmed against the actual trimmed
+/// line content, since two different lines can collide on a `u64`
+/// hash). These unique matches aren't necessarily monotonic on their
+/// own (e.g. a moved function reorders two unique lines), so they are
+/// reduced to their longest increasing subsequence by `rhs` line
+/// number, which pins `(left_line, right_line)` correspondences that
+/// hold even across inserted, deleted, or reordered blocks.
+pub fn build_line_anchors(lhs: &str, rhs: &str) -> Vec<(LineNumber, LineNumber)> {
+    let lhs_index = index_lines_by_hash(lhs);
+    let rhs_index = index_lines_by_hash(rhs);
+
+    let mut candidates: Vec<(LineNumber, LineNumber)> = lhs_index
+        .iter()
+        .filter(|(_, lhs_lines)| lhs_lines.len() == 1)
+        .filter_map(|(hash, lhs_lines)| {
+            let rhs_lines = rhs_index.get(hash)?;
+            if rhs_lines.len() != 1 {
+                return None;
+            }
+
+            let (lhs_line_num, lhs_line) = lhs_lines[0];
+            let (rhs_line_num, rhs_line) = rhs_lines[0];
+            if lhs_line != rhs_line {
+                // A hash collision between two different lines: not a
+                // real match.
+                return None;
+            }
+
+            Some((LineNumber::from(lhs_line_num), LineNumber::from(rhs_line_num)))
+        })
+        .collect();
+
+    candidates.sort_by_key(|(lhs_line, _)| lhs_line.number);
+    longest_increasing_rhs_subsequence(&candidates)
+}
+
+/// Reduce `candidates` (already sorted by `lhs` line number) to the
+/// longest subsequence whose `rhs` line numbers are also increasing, so
+/// the result is monotonic in both files. Standard patience-sorting
+/// O(n log n) longest-increasing-subsequence algorithm.
+fn longest_increasing_rhs_subsequence(
+    candidates: &[(LineNumber, LineNumber)],
+) -> Vec<(LineNumber, LineNumber)> {
+    // `tails[k]` is the index into `candidates` of the smallest possible
+    // tail value for an increasing subsequence of length k + 1.
+    let mut tails: Vec<usize> = vec![];
+    // `predecessors[i]` is the index into `candidates` of the previous
+    // element in the best subsequence ending at `i`, if any.
+    let mut predecessors: Vec<Option<usize>> = vec![None; candidates.len()];
+
+    for i in 0..candidates.len() {
+        let rhs = candidates[i].1.number;
+        let pos = tails.partition_point(|&t| candidates[t].1.number < rhs);
+
+        if pos > 0 {
+            predecessors[i] = Some(tails[pos - 1]);
+        }
+        if pos == tails.len() {
+            tails.push(i);
+        } else {
+            tails[pos] = i;
+        }
+    }
+
+    let mut result = vec![];
+    let mut next = tails.last().copied();
+    while let Some(i) = next {
+        result.push(candidates[i]);
+        next = predecessors[i];
+    }
+    result.reverse();
+    result
+}
+
+/// Find the opposite-file line for `line`, interpolating between the
+/// nearest anchors that bound it and clamping at the span edges.
+/// Falls back to `default_offset` (the assumption that both files line
+/// up) when `line` isn't bounded by an anchor on both sides, or if the
+/// bounding anchors turn out not to be monotonic.
+fn interpolate_opposite_line(
+    anchors: &[(LineNumber, LineNumber)],
+    line: LineNumber,
+    default_offset: isize,
+) -> LineNumber {
+    let prev = anchors
+        .iter()
+        .take_while(|(lhs, _)| lhs.number <= line.number)
+        .last();
+    let next = anchors
+        .iter()
+        .find(|(lhs, _)| lhs.number > line.number);
+
+    match (prev, next) {
+        (Some(&(prev_lhs, prev_rhs)), Some(&(next_lhs, next_rhs)))
+            if next_rhs.number >= prev_rhs.number =>
+        {
+            let span = next_lhs.number - prev_lhs.number;
+            let rhs_span = next_rhs.number - prev_rhs.number;
+            let offset_in_span = line.number - prev_lhs.number;
+            let interpolated = prev_rhs.number + (offset_in_span * rhs_span) / span;
+            return LineNumber::from(min(interpolated, next_rhs.number));
+        }
+        _ => {}
+    }
+
+    LineNumber::from(max(line.number as isize + default_offset, 0) as usize)
+}
+
+/// Add `context` lines of unchanged surrounding context around each
+/// matched line, up to `max_line`. This is `add_context_hunks` with the
+/// hunk boundaries flattened away; see that function for the grouping
+/// and anchor-interpolation behavior.
 pub fn add_context(
     lines: &[MatchedLine],
     context: usize,
     max_line: LineNumber,
+    anchors: &[(LineNumber, LineNumber)],
 ) -> Vec<MatchedLine> {
-    let mut result: Vec<MatchedLine> = vec![];
+    add_context_hunks(lines, context, max_line, anchors)
+        .into_iter()
+        .flatten()
+        .collect()
+}
+
+/// Like `add_context`, but groups the result into hunks: a new hunk
+/// starts whenever a line is not directly after the previous line of
+/// the result, i.e. when the context windows of two matched lines
+/// neither overlap nor touch, so callers can render `@@`/`...`
+/// separators between distant edits instead of a single flat run.
+pub fn add_context_hunks(
+    lines: &[MatchedLine],
+    context: usize,
+    max_line: LineNumber,
+    anchors: &[(LineNumber, LineNumber)],
+) -> Vec<Vec<MatchedLine>> {
+    let mut hunks: Vec<Vec<MatchedLine>> = vec![];
 
     for matched_line in lines {
         // We know the line number that matches this line. In order to
-        // calculate the opposite line number for the context lines,
-        // we assume that they line up. Context line -1 should have
-        // opposite_line - 1.
+        // calculate the opposite line number for the context lines, we
+        // assume that they line up unless `anchors` lets us interpolate
+        // a more accurate correspondence.
         let opposite_offset =
             matched_line.opposite_line.number as isize - matched_line.line.number as isize;
 
@@ -238,22 +631,41 @@ pub fn add_context(
         let latest = min(line_number + context, max_line.number);
 
         for i in earliest..latest + 1 {
-            let mut is_new = true;
-            if let Some(last_line) = result.last() {
-                if i <= last_line.line.number {
-                    is_new = false;
-                }
+            let last_line = hunks.last().and_then(|hunk| hunk.last());
+
+            let is_new = match last_line {
+                Some(last_line) => i > last_line.line.number,
+                None => true,
+            };
+            if !is_new {
+                continue;
             }
-            if is_new {
-                result.push(MatchedLine {
-                    line: LineNumber::from(i),
-                    opposite_line: LineNumber::from(max(i as isize + opposite_offset, 0) as usize),
-                });
+
+            let starts_new_hunk = match last_line {
+                Some(last_line) => i > last_line.line.number + 1,
+                None => true,
+            };
+            if starts_new_hunk {
+                hunks.push(vec![]);
             }
+
+            // The matched line itself already has an exact
+            // correspondence from the diff; only context lines need
+            // anchor interpolation.
+            let opposite_line = if i == line_number {
+                matched_line.opposite_line
+            } else {
+                interpolate_opposite_line(anchors, LineNumber::from(i), opposite_offset)
+            };
+
+            hunks.last_mut().unwrap().push(MatchedLine {
+                line: LineNumber::from(i),
+                opposite_line,
+            });
         }
     }
 
-    result
+    hunks
 }
 
 pub fn max_line(s: &str) -> LineNumber {
@@ -271,7 +683,7 @@ fn test_add_context() {
     }
 
     let start_lines = [matched_line(5), matched_line(12), matched_line(14)];
-    let result = add_context(&start_lines, 2, LineNumber::from(20));
+    let result = add_context(&start_lines, 2, LineNumber::from(20), &[]);
 
     let expected = [
         matched_line(3),
@@ -306,25 +718,176 @@ fn test_add_zero_context() {
             opposite_line: LineNumber::from(14),
         },
     ];
-    let result = add_context(&start_lines, 0, LineNumber::from(20));
+    let result = add_context(&start_lines, 0, LineNumber::from(20), &[]);
 
     assert_eq!(result, start_lines);
 }
 
-/// Ensure that every line in `s` has this length. Pad short lines and
-/// truncate long lines.
+#[test]
+fn test_add_context_uses_anchors_to_interpolate_opposite_line() {
+    // The matched line (5) keeps its exact, diff-computed opposite_line
+    // rather than being overwritten by interpolation; only the
+    // surrounding context lines (4 and 6) are interpolated using the
+    // anchors, which disagree with the constant offset implied by the
+    // matched line.
+    let start_lines = [MatchedLine {
+        line: LineNumber::from(5),
+        opposite_line: LineNumber::from(5),
+    }];
+    let anchors = [
+        (LineNumber::from(0), LineNumber::from(0)),
+        (LineNumber::from(10), LineNumber::from(15)),
+    ];
+
+    let result = add_context(&start_lines, 1, LineNumber::from(20), &anchors);
+
+    assert_eq!(
+        result,
+        vec![
+            MatchedLine {
+                line: LineNumber::from(4),
+                opposite_line: LineNumber::from(6),
+            },
+            MatchedLine {
+                line: LineNumber::from(5),
+                opposite_line: LineNumber::from(5),
+            },
+            MatchedLine {
+                line: LineNumber::from(6),
+                opposite_line: LineNumber::from(9),
+            },
+        ]
+    );
+}
+
+#[test]
+fn build_line_anchors_finds_unique_matches() {
+    let unique_a = "a".repeat(40);
+    let unique_b = "b".repeat(40);
+    let duplicated = "c".repeat(40);
+    let short = "short";
+
+    let lhs = format!(
+        "{}\n{}\n{}\n{}\n",
+        unique_a, duplicated, short, unique_b
+    );
+    let rhs = format!(
+        "{}\nextra\n{}\n{}\n{}\n",
+        unique_a, duplicated, short, unique_b
+    );
+    // `duplicated` occurs twice on the right, so it can't anchor.
+    let rhs = format!("{}{}\n", rhs, duplicated);
+
+    let anchors = build_line_anchors(&lhs, &rhs);
+
+    assert_eq!(
+        anchors,
+        vec![
+            (LineNumber::from(0), LineNumber::from(0)),
+            (LineNumber::from(3), LineNumber::from(4)),
+        ]
+    );
+}
+
+#[test]
+fn build_line_anchors_drops_non_monotonic_matches() {
+    // `unique_a` and `unique_b` are both unique on each side, but their
+    // relative order is swapped between `lhs` and `rhs` (e.g. two
+    // functions were reordered). Only the longest increasing-by-rhs
+    // subsequence should survive, so the anchors stay monotonic.
+    let unique_a = "a".repeat(40);
+    let unique_b = "b".repeat(40);
+
+    let lhs = format!("{}\n{}\n", unique_a, unique_b);
+    let rhs = format!("{}\n{}\n", unique_b, unique_a);
+
+    let anchors = build_line_anchors(&lhs, &rhs);
+
+    assert_eq!(anchors.len(), 1);
+    let (lhs_line, rhs_line) = anchors[0];
+    assert!(
+        lhs_line == LineNumber::from(0) && rhs_line == LineNumber::from(1)
+            || lhs_line == LineNumber::from(1) && rhs_line == LineNumber::from(0)
+    );
+}
+
+#[test]
+fn interpolate_opposite_line_falls_back_on_non_monotonic_anchors() {
+    // Even if malformed anchors slip through, interpolation must not
+    // panic on a `usize` underflow and should fall back to the
+    // constant-offset assumption instead.
+    let anchors = [
+        (LineNumber::from(0), LineNumber::from(5)),
+        (LineNumber::from(3), LineNumber::from(1)),
+    ];
+
+    let result = interpolate_opposite_line(&anchors, LineNumber::from(1), 2);
+    assert_eq!(result, LineNumber::from(3));
+}
+
+#[test]
+fn test_add_context_hunks() {
+    fn matched_line(i: usize) -> MatchedLine {
+        MatchedLine {
+            line: LineNumber::from(i),
+            opposite_line: LineNumber::from(i),
+        }
+    }
+
+    let start_lines = [matched_line(5), matched_line(12), matched_line(14)];
+    let result = add_context_hunks(&start_lines, 2, LineNumber::from(20), &[]);
+
+    let expected = vec![
+        vec![
+            matched_line(3),
+            matched_line(4),
+            matched_line(5),
+            matched_line(6),
+            matched_line(7),
+        ],
+        vec![
+            matched_line(10),
+            matched_line(11),
+            matched_line(12),
+            matched_line(13),
+            matched_line(14),
+            matched_line(15),
+            matched_line(16),
+        ],
+    ];
+    assert_eq!(result, expected);
+}
+
+/// Ensure that every line in `s` has this display length. Pad short
+/// lines and truncate long lines, measuring in terminal columns rather
+/// than bytes: each grapheme cluster counts for its display width (0
+/// for combining marks, 2 for double-width characters such as CJK),
+/// and truncation always falls on a grapheme cluster boundary. The
+/// line terminator of `s`'s first line (see `LineTerminator::detect`)
+/// ends every line of the result, so a file with mixed line endings
+/// comes out with a single one.
 pub fn enforce_length(s: &str, line_length: usize) -> String {
+    let terminator = LineTerminator::detect(s);
+
     let mut result = String::with_capacity(s.len());
     for line in s.lines() {
-        // TODO: use length in chars not bytes.
-        if line.len() > line_length {
-            // Truncate.
-            result.push_str(&line[0..line_length]);
-            result.push_str("\n");
-        } else {
-            // Pad with spaces.
-            result.push_str(&format!("{:width$}\n", line, width = line_length));
+        // `str::lines` already strips both `\n` and `\r\n`.
+        let mut width = 0;
+        let mut truncated = String::with_capacity(line.len());
+        for grapheme in line.graphemes(true) {
+            let grapheme_width = grapheme.width();
+            if width + grapheme_width > line_length {
+                break;
+            }
+            width += grapheme_width;
+            truncated.push_str(grapheme);
+        }
+
+        result.push_str(&truncated);
+        for _ in width..line_length {
+            result.push(' ');
         }
+        result.push_str(terminator.as_str());
     }
 
     result
@@ -341,3 +904,22 @@ fn enforce_length_long() {
     let result = enforce_length("foobar\nbarbaz\n", 3);
     assert_eq!(result, "foo\nbar\n");
 }
+
+#[test]
+fn enforce_length_crlf() {
+    let result = enforce_length("foo\r\nbar\r\n", 5);
+    assert_eq!(result, "foo  \r\nbar  \r\n");
+}
+
+#[test]
+fn enforce_length_double_width_chars() {
+    let result = enforce_length("好\n", 4);
+    assert_eq!(result, "好  \n");
+}
+
+#[test]
+fn enforce_length_truncates_on_grapheme_boundary() {
+    // "e\u{301}" is a single grapheme cluster (e + combining acute accent).
+    let result = enforce_length("e\u{301}fgh\n", 2);
+    assert_eq!(result, "e\u{301}f\n");
+}